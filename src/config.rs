@@ -0,0 +1,74 @@
+use std::{fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// The current on-disk config schema version. Bump this whenever a field is
+/// added or removed in a way that older configs need migrating for.
+const CONFIG_VERSION: u32 = 1;
+
+const CONFIG_FILE_NAME: &str = "proxy-address-blocker.toml";
+
+/// The durable settings persisted between sessions: port, bind interface and
+/// blocklist. Stored as human-editable TOML rather than via `eframe`'s opaque
+/// storage blob so users can back it up or hand-edit it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(default)]
+    pub port: String,
+    #[serde(default)]
+    pub bind_address: String,
+    #[serde(default)]
+    pub blocked_hosts: Vec<String>,
+}
+
+fn default_version() -> u32 {
+    CONFIG_VERSION
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            port: String::default(),
+            bind_address: String::default(),
+            blocked_hosts: Vec::default(),
+        }
+    }
+}
+
+impl Config {
+    fn path() -> PathBuf {
+        PathBuf::from(CONFIG_FILE_NAME)
+    }
+
+    /// Loads the config from disk, falling back to defaults if it's missing or
+    /// unreadable. Fields absent from an older config (e.g. one predating
+    /// `bind_address`) are filled in via `#[serde(default)]` rather than
+    /// failing to parse.
+    ///
+    /// If the file exists but fails to parse (e.g. corrupt TOML or a field of
+    /// the wrong type), the unreadable file is moved aside to `*.bak` instead
+    /// of being silently discarded, so the user's port/blocklist settings can
+    /// still be recovered by hand. The `version` key is not checked: a config
+    /// with a newer version that still parses is loaded like any other.
+    pub fn load() -> Self {
+        let path = Self::path();
+        let Ok(contents) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+
+        toml::from_str(&contents).unwrap_or_else(|_| {
+            let _ = fs::rename(&path, path.with_extension("toml.bak"));
+            Self::default()
+        })
+    }
+
+    /// Rewrites the config file with the current settings.
+    pub fn save(&self) {
+        if let Ok(contents) = toml::to_string_pretty(self) {
+            let _ = fs::write(Self::path(), contents);
+        }
+    }
+}
@@ -1,9 +1,91 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use eframe::{
-    egui::{self, CentralPanel},
+    egui::{self, CentralPanel, ViewportBuilder, ViewportId},
     epaint::{Color32, Stroke, Vec2},
 };
 
-use crate::{main_body, proxy::Proxy, task_bar};
+use crate::{
+    config::Config,
+    main_body,
+    service::proxy::{Proxy, ProxyEvent, ProxyRequestLog, ToxicDirection, UpstreamScheme},
+    task_bar,
+};
+
+/// Storage key the blocklist is saved/loaded under, kept separate from `eframe::APP_KEY`
+/// so it can be exported/imported independently of the rest of the persisted app state.
+const BLOCKLIST_STORAGE_KEY: &str = "proxy-address-blocker.blocklist";
+
+/// Default value of `auto_save_interval_secs` until the user tunes it.
+const DEFAULT_AUTO_SAVE_INTERVAL_SECS: u64 = 5;
+
+/// Fixed size of the main control window; the log viewer lives in its own viewport
+/// instead of resizing this one out from under the user.
+const MAIN_WINDOW_SIZE: Vec2 = Vec2 { x: 250.0, y: 160.0 };
+
+/// A small, user-editable color palette used when `Theme::Custom` is selected.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct CustomTheme {
+    pub background: Color32,
+    pub accent: Color32,
+    pub text: Color32,
+}
+
+impl Default for CustomTheme {
+    fn default() -> Self {
+        Self {
+            background: Color32::from_rgb(30, 30, 30),
+            accent: Color32::from_rgb(90, 140, 220),
+            text: Color32::WHITE,
+        }
+    }
+}
+
+/// The color palette applied to the whole app each frame. Lives on `MainWindow` so it
+/// round-trips through `eframe::get_value`/`set_value` and survives restarts.
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Default)]
+pub enum Theme {
+    #[default]
+    Dark,
+    Light,
+    Custom(CustomTheme),
+}
+
+impl Theme {
+    /// Builds the `egui::Visuals` this theme applies to the whole app.
+    fn visuals(&self) -> egui::Visuals {
+        match self {
+            Theme::Dark => egui::Visuals::dark(),
+            Theme::Light => egui::Visuals::light(),
+            Theme::Custom(custom) => {
+                let mut visuals = egui::Visuals::dark();
+                visuals.window_fill = custom.background;
+                visuals.panel_fill = custom.background;
+                visuals.selection.bg_fill = custom.accent;
+                visuals.widgets.noninteractive.bg_stroke.color = custom.accent;
+                visuals.widgets.inactive.bg_stroke.color = custom.accent;
+                visuals.override_text_color = Some(custom.text);
+                visuals
+            }
+        }
+    }
+}
+
+/// Which kind of Toxic the "Network Toxics" section's builder is currently staged
+/// to add; purely a UI selector, not persisted alongside the toxics themselves
+/// (those live on `Proxy` and are cleared on restart regardless).
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum ToxicKind {
+    #[default]
+    Latency,
+    Bandwidth,
+    SlowClose,
+    Timeout,
+}
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
@@ -12,34 +94,303 @@ pub struct MainWindow {
     pub minimise_button_tint: Color32,
     pub maximise_button_tint: Color32,
 
-    // Handle all Proxy Details
+    // The color palette applied to the app, beyond the three button tints above
+    pub theme: Theme,
+
+    // Port entry and validation
+    pub port: String,
+    pub port_error: String,
+    pub start_server_capable: bool,
+    // Whether `port` currently holds a valid value, tracked separately from
+    // `bind_address_valid` so editing one field can't stomp on the other's
+    // contribution to `start_server_capable`.
+    #[serde(skip)]
+    port_valid: bool,
+
+    // Bind address entry and validation, e.g. "0.0.0.0", "0.0.0.0:8000" or a specific
+    // interface IP
+    pub bind_address: String,
+    pub bind_address_error: String,
+    #[serde(skip)]
+    bind_address_valid: bool,
+
+    // Whether the detached log viewer viewport is currently shown
+    pub show_logs: bool,
+    // Free-text filter applied to the rendered log panel. Shared so the log viewport's
+    // deferred closure, which has no access to `&mut self`, can read and edit it.
+    #[serde(skip)]
+    pub log_filter: Arc<Mutex<String>>,
+
+    // Hosts currently blocked by the proxy
+    pub blocked_hosts: Vec<String>,
+    // Path used by the "Export to File"/"Import from File" blocklist buttons
+    pub blocklist_file_path: String,
+
+    // Optional upstream parent proxy to chain outbound connections through
+    pub upstream_scheme: UpstreamScheme,
+    pub upstream_address: String,
+    pub upstream_username: String,
+    pub upstream_password: String,
+
+    // Comma-separated "host:port" backends to round-robin allowed connections across
+    pub backend_pool_input: String,
+
+    // Optional caps on concurrent/new connections, parsed onto Proxy::max_connections
+    // and Proxy::max_connection_rate when the user clicks Start
+    pub max_connections_input: String,
+    pub max_connection_rate_input: String,
+
+    // Optional NDJSON file every request is additionally appended to, parsed onto
+    // Proxy::request_log_path when the user clicks Start
+    pub request_log_path_input: String,
+
+    // Staged inputs for the "Network Toxics" builder
+    #[serde(skip)]
+    pub toxic_kind: ToxicKind,
+    #[serde(skip)]
+    pub toxic_direction: ToxicDirection,
+    pub toxic_ms_input: String,
+    pub toxic_jitter_input: String,
+    pub toxic_rate_input: String,
+
+    // The proxy engine itself - owns its own running state, traffic filter, request
+    // log and every other runtime field; the fields above are just staged UI input
+    // copied across onto it when the user clicks Start.
     pub proxy: Proxy,
+
+    // Set from within the log viewer's viewport when the user closes it, since the
+    // deferred closure can't flip `show_logs` on `self` directly.
+    #[serde(skip)]
+    log_viewport_close_requested: Arc<Mutex<bool>>,
+
+    // How often `eframe` is allowed to call `save` automatically, user-tunable so heavy
+    // proxy logging doesn't force storage writes more often than necessary.
+    pub auto_save_interval_secs: u64,
+    // Set whenever a persisted field changes; `save` skips the write entirely when this
+    // is false, since the default cadence would otherwise blindly reserialize everything
+    // (including the Mutex-wrapped proxy fields) on every tick.
+    #[serde(skip)]
+    dirty: bool,
 }
 
 impl Default for MainWindow {
     fn default() -> Self {
-        let proxy = Proxy::default();
-
         Self {
             close_button_tint: Color32::WHITE,
             minimise_button_tint: Color32::WHITE,
             maximise_button_tint: Color32::WHITE,
 
-            proxy,
+            theme: Theme::default(),
+
+            port: String::default(),
+            port_error: String::default(),
+            start_server_capable: true,
+            port_valid: true,
+
+            bind_address: String::from("127.0.0.1"),
+            bind_address_error: String::default(),
+            bind_address_valid: true,
+
+            show_logs: false,
+            log_filter: Arc::new(Mutex::new(String::default())),
+            blocked_hosts: Vec::default(),
+            blocklist_file_path: String::from("blocklist.json"),
+
+            upstream_scheme: UpstreamScheme::Http,
+            upstream_address: String::default(),
+            upstream_username: String::default(),
+            upstream_password: String::default(),
+
+            backend_pool_input: String::default(),
+
+            max_connections_input: String::default(),
+            max_connection_rate_input: String::default(),
+            request_log_path_input: String::default(),
+
+            toxic_kind: ToxicKind::default(),
+            toxic_direction: ToxicDirection::default(),
+            toxic_ms_input: String::default(),
+            toxic_jitter_input: String::default(),
+            toxic_rate_input: String::default(),
+
+            proxy: Proxy::default(),
+
+            log_viewport_close_requested: Arc::new(Mutex::new(false)),
+
+            auto_save_interval_secs: DEFAULT_AUTO_SAVE_INTERVAL_SECS,
+            dirty: true,
         }
     }
 }
 
 impl MainWindow {
     pub fn new(cc: &eframe::CreationContext<'_>) -> Self {
-        if let Some(storage) = cc.storage {
+        let mut window = if let Some(storage) = cc.storage {
             // We can manipulate Proxy here, might be worth setting some default values
             // Maybe a custom impl function to overwrite some items
             // Mutex doesn't like being copied over
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+            eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
+        } else {
+            Self::default()
+        };
+
+        // The TOML config is the durable, human-editable source of truth for the
+        // port and blocklist; it takes priority over whatever eframe persisted.
+        let config = Config::load();
+        window.port = config.port;
+        window.blocked_hosts = config.blocked_hosts;
+        if !config.bind_address.is_empty() {
+            window.bind_address = config.bind_address;
+        }
+
+        window
+    }
+
+    /// Rewrites the on-disk TOML config from the window's current durable fields
+    /// immediately, bypassing the dirty/autosave throttle. Reserved for points where
+    /// a write is genuinely time-critical (e.g. `on_exit`, where there's no later
+    /// autosave tick to fall back on) — UI field edits should call `mark_dirty`
+    /// instead and let the throttled `save` flush it.
+    pub fn save_config(&mut self) {
+        self.write_config_to_disk();
+        self.mark_dirty();
+    }
+
+    /// Serializes the window's current durable fields to the on-disk TOML config.
+    fn write_config_to_disk(&self) {
+        Config {
+            port: self.port.clone(),
+            bind_address: self.bind_address.clone(),
+            blocked_hosts: self.blocked_hosts.clone(),
+            ..Config::default()
+        }
+        .save();
+    }
+
+    /// Flags a persisted field as changed, so the next `eframe` autosave tick actually
+    /// writes storage instead of skipping a no-op reserialization.
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Records whether `port` currently holds a valid value, and recomputes
+    /// `start_server_capable` from both field validities so one field's check can't
+    /// clobber the other's.
+    pub fn set_port_validity(&mut self, valid: bool) {
+        self.port_valid = valid;
+        self.start_server_capable = self.port_valid && self.bind_address_valid;
+    }
+
+    /// Records whether `bind_address` currently holds a valid value, and recomputes
+    /// `start_server_capable` from both field validities so one field's check can't
+    /// clobber the other's.
+    pub fn set_bind_address_validity(&mut self, valid: bool) {
+        self.bind_address_valid = valid;
+        self.start_server_capable = self.port_valid && self.bind_address_valid;
+    }
+
+    /// Serializes the current blocklist into `storage` under its own key, so it can be
+    /// restored with `import_blocklist_from_storage` independently of the full app-state
+    /// snapshot that `save`/`eframe::APP_KEY` round-trip.
+    pub fn export_blocklist_to_storage(&self, storage: &mut dyn eframe::Storage) {
+        if let Ok(json) = serde_json::to_string(&self.blocked_hosts) {
+            storage.set_string(BLOCKLIST_STORAGE_KEY, json);
+        }
+    }
+
+    /// Restores the blocklist previously saved with `export_blocklist_to_storage`, leaving
+    /// the current list untouched if no blob is present or it fails to parse.
+    pub fn import_blocklist_from_storage(&mut self, storage: &dyn eframe::Storage) {
+        let Some(json) = storage.get_string(BLOCKLIST_STORAGE_KEY) else {
+            return;
+        };
+
+        if let Ok(hosts) = serde_json::from_str(&json) {
+            self.blocked_hosts = hosts;
+            self.mark_dirty();
         }
+    }
+
+    /// Writes the current blocklist to `blocklist_file_path` as a standalone JSON file,
+    /// so it can be copied to and imported on another machine.
+    pub fn export_blocklist_to_file(&self) -> std::io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.blocked_hosts)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+        std::fs::write(&self.blocklist_file_path, json)
+    }
+
+    /// Reads a blocklist previously written by `export_blocklist_to_file` and replaces the
+    /// current list with it.
+    pub fn import_blocklist_from_file(&mut self) -> std::io::Result<()> {
+        let contents = std::fs::read_to_string(&self.blocklist_file_path)?;
+
+        self.blocked_hosts = serde_json::from_str(&contents)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
+
+        self.mark_dirty();
+        Ok(())
+    }
+
+    /// Renders the log viewer's contents: a filter box and a "Clear"/"Copy" toolbar over
+    /// a scrolling, monospace request list. Runs inside the detached viewport's deferred
+    /// closure, so it only touches state reachable through shared handles, not `&mut self`.
+    fn render_log_viewport(
+        ui: &mut egui::Ui,
+        requests: &Arc<Mutex<std::collections::VecDeque<ProxyRequestLog>>>,
+        log_filter: &Arc<Mutex<String>>,
+    ) {
+        ui.add_space(4.0);
+
+        ui.horizontal(|ui| {
+            let mut filter = log_filter.lock().unwrap();
+            ui.add(egui::TextEdit::singleline(&mut *filter).hint_text("Filter requests..."));
 
-        Default::default()
+            if ui.button("Clear").clicked() {
+                requests.lock().unwrap().clear();
+            }
+
+            if ui.button("Copy").clicked() {
+                let text = requests
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|request| {
+                        format!(
+                            "{} {} -> {}",
+                            request.method,
+                            request.request,
+                            if request.blocked { "BLOCKED" } else { "ALLOWED" }
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+
+                ui.output_mut(|output| output.copied_text = text);
+            }
+        });
+
+        ui.add_space(4.0);
+
+        let filter = log_filter.lock().unwrap().to_lowercase();
+        let requests = requests.lock().unwrap();
+
+        egui::ScrollArea::vertical()
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for request in requests.iter() {
+                    let line = format!(
+                        "{} {} -> {}",
+                        request.method,
+                        request.request,
+                        if request.blocked { "BLOCKED" } else { "ALLOWED" }
+                    );
+
+                    if filter.is_empty() || line.to_lowercase().contains(&filter) {
+                        ui.label(egui::RichText::new(line).monospace());
+                    }
+                }
+            });
     }
 }
 
@@ -49,16 +400,47 @@ impl eframe::App for MainWindow {
     }
 
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
-        if self.proxy.logs && !frame.info().window_info.maximized {
-            frame.set_window_size(Vec2 { x: 650.0, y: 500.0 });
-        } else if !self.proxy.logs && !frame.info().window_info.maximized {
-            frame.set_window_size(Vec2 { x: 250.0, y: 160.0 });
+        if std::mem::take(&mut *self.log_viewport_close_requested.lock().unwrap()) {
+            self.show_logs = false;
+        }
+
+        let visuals = self.theme.visuals();
+        ctx.set_visuals(visuals.clone());
+
+        if !frame.info().window_info.maximized {
+            frame.set_window_size(MAIN_WINDOW_SIZE);
+        }
+
+        if self.show_logs {
+            let requests = self.proxy.requests.clone();
+            let log_filter = self.log_filter.clone();
+            let close_requested = self.log_viewport_close_requested.clone();
+            let viewport_visuals = visuals.clone();
+
+            ctx.show_viewport_deferred(
+                ViewportId::from_hash_of("log_viewer"),
+                ViewportBuilder::default()
+                    .with_title("Request Log")
+                    .with_inner_size([650.0, 500.0])
+                    .with_resizable(true),
+                move |ctx, _class| {
+                    ctx.set_visuals(viewport_visuals.clone());
+
+                    CentralPanel::default().show(ctx, |ui| {
+                        Self::render_log_viewport(ui, &requests, &log_filter);
+                    });
+
+                    if ctx.input(|i| i.viewport().close_requested()) {
+                        *close_requested.lock().unwrap() = true;
+                    }
+                },
+            );
         }
 
         let panel_frame = egui::Frame {
-            fill: ctx.style().visuals.window_fill(),
+            fill: visuals.window_fill(),
             rounding: 7.0.into(),
-            stroke: Stroke::new(1.0, Color32::LIGHT_GRAY),
+            stroke: Stroke::new(1.0, visuals.selection.bg_fill),
             outer_margin: 0.1.into(),
             ..Default::default()
         };
@@ -66,16 +448,46 @@ impl eframe::App for MainWindow {
         CentralPanel::default().frame(panel_frame).show(ctx, |ui| {
             ui.with_layout(egui::Layout::top_down(egui::Align::Center), |ui| {
                 task_bar::task_bar(self, ui, frame);
-                main_body::main_body(&mut self.proxy, ui);
+                main_body::main_body(self, ui, frame.storage_mut());
             });
         });
     }
 
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
+        if !self.dirty {
+            return;
+        }
+
+        self.write_config_to_disk();
         eframe::set_value(storage, eframe::APP_KEY, self);
+        self.dirty = false;
     }
 
     fn persist_native_window(&self) -> bool {
         true
     }
+
+    fn auto_save_interval(&self) -> Duration {
+        Duration::from_secs(self.auto_save_interval_secs)
+    }
+
+    /// Asks a running proxy to wind down before the window closes, and briefly waits
+    /// for it to actually finish so the listener and worker tasks aren't dropped
+    /// mid-flight. Caps the wait so a stuck shutdown can't hang the window close.
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        if matches!(self.proxy.get_status(), ProxyEvent::Running | ProxyEvent::Starting) {
+            self.proxy.stop();
+
+            let shutdown_deadline = std::time::Instant::now() + Duration::from_secs(2);
+            while std::time::Instant::now() < shutdown_deadline {
+                if matches!(self.proxy.get_status(), ProxyEvent::Stopped) {
+                    break;
+                }
+
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+
+        self.save_config();
+    }
 }
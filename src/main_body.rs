@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::mpsc::Sender, thread};
+use std::net::{IpAddr, SocketAddr};
 
 use eframe::{
     egui::{self, CentralPanel, Label, Margin, RichText, TextEdit},
@@ -6,15 +6,14 @@ use eframe::{
 };
 
 use crate::{
-    default_window::{MainWindow, ProxyEvent},
-    proxy_handler::proxy_service,
+    default_window::{CustomTheme, MainWindow, Theme, ToxicKind},
+    service::proxy::{BackendPool, ProxyEvent, Toxic, ToxicDirection, UpstreamProxy, UpstreamScheme},
 };
 
 pub fn main_body(
     properties: &mut MainWindow,
     ui: &mut egui::Ui,
-    proxy_event_sender: Sender<ProxyEvent>,
-    // request_event_sender: Sender<RequestEvent>,
+    mut storage: Option<&mut dyn eframe::Storage>,
 ) {
     let panel_frame = egui::Frame {
         fill: ui.ctx().style().visuals.window_fill(),
@@ -31,18 +30,12 @@ pub fn main_body(
     CentralPanel::default()
         .frame(panel_frame)
         .show(ui.ctx(), |ui| {
-            let current_proxy_state = match properties.proxy_status.lock() {
-                Ok(proxy_event) => proxy_event,
-                Err(poisoned) => poisoned.into_inner(),
-            };
-
-            match *current_proxy_state {
-                ProxyEvent::Error => {
-                    properties.port_error = "Please check the port is available.".to_string();
-                    properties.start_server_capable = true;
-                }
-                _ => (),
-            };
+            let current_proxy_state = properties.proxy.get_status();
+
+            if let ProxyEvent::Error(_) = &current_proxy_state {
+                properties.port_error = "Please check the port is available.".to_string();
+                properties.set_port_validity(true);
+            }
 
             let label = Label::new("Enter a Port to run on:");
             ui.add(label);
@@ -55,28 +48,28 @@ pub fn main_body(
                 // TODO: Something about this mess, there is definitely a nicer way
                 if properties.port.char_indices().count() < 2 {
                     properties.port_error = "Port too short!".to_string();
+                    properties.set_port_validity(false);
                     return;
-                } else {
-                    properties.start_server_capable = true;
-                    properties.port_error = String::default();
                 }
 
                 if properties.port.char_indices().count() > 5 {
                     properties.port_error = "Port too long!".to_string();
+                    properties.set_port_validity(false);
                     return;
-                } else {
-                    properties.start_server_capable = true;
-                    properties.port_error = String::default();
                 }
 
-                if let Err(_) = properties.port.trim().parse::<u32>() {
+                if let Err(_) = properties.port.trim().parse::<u16>() {
                     properties.port_error = "Port contains invalid characters.".to_string();
-                    properties.start_server_capable = false;
+                    properties.set_port_validity(false);
                     return;
                 } else {
-                    properties.start_server_capable = true;
                     properties.port_error = String::default();
+                    properties.set_port_validity(true);
                 }
+
+                // Queue the validated port for the next throttled autosave tick
+                // instead of writing to disk on every keystroke.
+                properties.mark_dirty();
             }
 
             if !properties.port_error.is_empty() {
@@ -85,9 +78,298 @@ pub fn main_body(
                 ui.label(RichText::new(&properties.port_error).color(Color32::LIGHT_RED));
             }
 
+            ui.add_space(4.0);
+            let label = Label::new("Bind address:");
+            ui.add(label);
+            ui.add_space(2.0);
+
+            let bind_input = TextEdit::singleline(&mut properties.bind_address)
+                .hint_text("127.0.0.1, 0.0.0.0, 0.0.0.0:8000, ...");
+            let bind_input_response = ui.add(bind_input);
+
+            if bind_input_response.changed() {
+                match parse_bind_address(&properties.bind_address) {
+                    Ok((address, port)) if !address.is_loopback() => {
+                        properties.bind_address_error =
+                            "Warning: binding to a non-loopback address exposes the proxy externally."
+                                .to_string();
+                        properties.set_bind_address_validity(true);
+                        if let Some(port) = port {
+                            properties.port = port.to_string();
+                        }
+                    }
+                    Ok((_, port)) => {
+                        properties.bind_address_error = String::default();
+                        properties.set_bind_address_validity(true);
+                        if let Some(port) = port {
+                            properties.port = port.to_string();
+                        }
+                    }
+                    Err(_) => {
+                        properties.bind_address_error = "Bind address is invalid.".to_string();
+                        properties.set_bind_address_validity(false);
+                    }
+                }
+
+                properties.mark_dirty();
+            }
+
+            if !properties.bind_address_error.is_empty() {
+                ui.add_space(3.0);
+                ui.label(RichText::new(&properties.bind_address_error).color(Color32::LIGHT_RED));
+            }
+
+            let mut theme_changed = false;
+
+            ui.collapsing("Theme", |ui| {
+                egui::ComboBox::from_label("Palette")
+                    .selected_text(match properties.theme {
+                        Theme::Dark => "Dark",
+                        Theme::Light => "Light",
+                        Theme::Custom(_) => "Custom",
+                    })
+                    .show_ui(ui, |ui| {
+                        theme_changed |=
+                            ui.selectable_value(&mut properties.theme, Theme::Dark, "Dark").changed();
+                        theme_changed |=
+                            ui.selectable_value(&mut properties.theme, Theme::Light, "Light").changed();
+                        theme_changed |= ui
+                            .selectable_value(
+                                &mut properties.theme,
+                                Theme::Custom(CustomTheme::default()),
+                                "Custom",
+                            )
+                            .changed();
+                    });
+
+                if let Theme::Custom(custom) = &mut properties.theme {
+                    ui.horizontal(|ui| {
+                        ui.label("Background:");
+                        theme_changed |= ui.color_edit_button_srgba(&mut custom.background).changed();
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Accent:");
+                        theme_changed |= ui.color_edit_button_srgba(&mut custom.accent).changed();
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Text:");
+                        theme_changed |= ui.color_edit_button_srgba(&mut custom.text).changed();
+                    });
+                }
+            });
+
+            if theme_changed {
+                properties.mark_dirty();
+            }
+
+            ui.collapsing("Persistence", |ui| {
+                ui.label("Auto-save interval (seconds):");
+                let slider =
+                    egui::Slider::new(&mut properties.auto_save_interval_secs, 1..=120);
+                if ui.add(slider).changed() {
+                    properties.mark_dirty();
+                }
+            });
+
+            ui.collapsing("Upstream Proxy", |ui| {
+                egui::ComboBox::from_label("Scheme")
+                    .selected_text(match properties.upstream_scheme {
+                        UpstreamScheme::Http => "HTTP CONNECT",
+                        UpstreamScheme::Socks5 => "SOCKS5",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut properties.upstream_scheme,
+                            UpstreamScheme::Http,
+                            "HTTP CONNECT",
+                        );
+                        ui.selectable_value(
+                            &mut properties.upstream_scheme,
+                            UpstreamScheme::Socks5,
+                            "SOCKS5",
+                        );
+                    });
+                ui.add(
+                    TextEdit::singleline(&mut properties.upstream_address)
+                        .hint_text("host:port (optional)"),
+                );
+                ui.add(
+                    TextEdit::singleline(&mut properties.upstream_username)
+                        .hint_text("Username (optional)"),
+                );
+                ui.add(
+                    TextEdit::singleline(&mut properties.upstream_password)
+                        .password(true)
+                        .hint_text("Password (optional)"),
+                );
+            });
+
+            ui.collapsing("HTTPS Interception (MITM)", |ui| {
+                let mut mitm_enabled = properties.proxy.mitm_enabled;
+                if ui.checkbox(&mut mitm_enabled, "Intercept CONNECT tunnels").changed() {
+                    properties.proxy.toggle_mitm();
+                }
+
+                ui.add(
+                    TextEdit::singleline(&mut properties.proxy.ca_cert_path)
+                        .hint_text("Path to CA certificate (PEM)"),
+                );
+                ui.add(
+                    TextEdit::singleline(&mut properties.proxy.ca_key_path)
+                        .hint_text("Path to CA private key (PEM)"),
+                );
+            });
+
+            ui.collapsing("Backend Pool", |ui| {
+                ui.add(
+                    TextEdit::singleline(&mut properties.backend_pool_input)
+                        .hint_text("host:port, host:port, ... (optional)"),
+                );
+
+                if let Some(pool) = &properties.proxy.backend_pool {
+                    for (backend, live_connections) in pool.live_connections() {
+                        ui.label(format!("{backend} -> {live_connections} live"));
+                    }
+                }
+            });
+
+            ui.collapsing("Request Log Export", |ui| {
+                ui.add(
+                    TextEdit::singleline(&mut properties.request_log_path_input)
+                        .hint_text("requests.ndjson (optional)"),
+                );
+            });
+
+            ui.collapsing("Connection Limits", |ui| {
+                ui.add(
+                    TextEdit::singleline(&mut properties.max_connections_input)
+                        .hint_text("Max concurrent connections (optional)"),
+                );
+                ui.add(
+                    TextEdit::singleline(&mut properties.max_connection_rate_input)
+                        .hint_text("Max new connections/sec (optional)"),
+                );
+
+                ui.label(format!("{} live connection(s)", properties.proxy.get_live_connections()));
+            });
+
+            ui.collapsing("Network Toxics", |ui| {
+                egui::ComboBox::from_label("Direction")
+                    .selected_text(match properties.toxic_direction {
+                        ToxicDirection::Upstream => "Upstream",
+                        ToxicDirection::Downstream => "Downstream",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(
+                            &mut properties.toxic_direction,
+                            ToxicDirection::Upstream,
+                            "Upstream",
+                        );
+                        ui.selectable_value(
+                            &mut properties.toxic_direction,
+                            ToxicDirection::Downstream,
+                            "Downstream",
+                        );
+                    });
+
+                egui::ComboBox::from_label("Kind")
+                    .selected_text(match properties.toxic_kind {
+                        ToxicKind::Latency => "Latency",
+                        ToxicKind::Bandwidth => "Bandwidth",
+                        ToxicKind::SlowClose => "Slow Close",
+                        ToxicKind::Timeout => "Timeout",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut properties.toxic_kind, ToxicKind::Latency, "Latency");
+                        ui.selectable_value(&mut properties.toxic_kind, ToxicKind::Bandwidth, "Bandwidth");
+                        ui.selectable_value(&mut properties.toxic_kind, ToxicKind::SlowClose, "Slow Close");
+                        ui.selectable_value(&mut properties.toxic_kind, ToxicKind::Timeout, "Timeout");
+                    });
+
+                match properties.toxic_kind {
+                    ToxicKind::Latency => {
+                        ui.add(TextEdit::singleline(&mut properties.toxic_ms_input).hint_text("Latency (ms)"));
+                        ui.add(TextEdit::singleline(&mut properties.toxic_jitter_input).hint_text("Jitter (ms, optional)"));
+                    }
+                    ToxicKind::Bandwidth => {
+                        ui.add(TextEdit::singleline(&mut properties.toxic_rate_input).hint_text("Rate (kbps)"));
+                    }
+                    ToxicKind::SlowClose | ToxicKind::Timeout => {
+                        ui.add(TextEdit::singleline(&mut properties.toxic_ms_input).hint_text("Delay (ms)"));
+                    }
+                }
+
+                ui.horizontal(|ui| {
+                    if ui.button("Add Toxic").clicked() {
+                        let ms = properties.toxic_ms_input.trim().parse::<u64>().unwrap_or(0);
+                        let jitter = properties.toxic_jitter_input.trim().parse::<u64>().unwrap_or(0);
+                        let rate_kbps = properties.toxic_rate_input.trim().parse::<u64>().unwrap_or(0);
+
+                        let toxic = match properties.toxic_kind {
+                            ToxicKind::Latency => Toxic::Latency { ms, jitter },
+                            ToxicKind::Bandwidth => Toxic::Bandwidth { rate_kbps },
+                            ToxicKind::SlowClose => Toxic::SlowClose { ms },
+                            ToxicKind::Timeout => Toxic::Timeout { ms },
+                        };
+
+                        properties.proxy.add_toxic(properties.toxic_direction, toxic);
+                    }
+
+                    if ui.button("Clear Toxics").clicked() {
+                        properties.proxy.clear_toxics();
+                    }
+                });
+
+                for (direction, toxic) in properties.proxy.get_toxics() {
+                    ui.label(format!("{direction:?}: {toxic:?}"));
+                }
+            });
+
+            ui.collapsing("Blocklist Import/Export", |ui| {
+                let mut sni_filtering_enabled = properties.proxy.sni_filtering_enabled;
+                if ui
+                    .checkbox(&mut sni_filtering_enabled, "Also filter CONNECT tunnels by SNI hostname")
+                    .changed()
+                {
+                    properties.proxy.toggle_sni_filtering();
+                }
+
+                ui.label(format!("{} rule(s) currently loaded", properties.blocked_hosts.len()));
+
+                ui.horizontal(|ui| {
+                    if ui.button("Export to Storage").clicked() {
+                        if let Some(storage) = storage.as_deref_mut() {
+                            properties.export_blocklist_to_storage(storage);
+                        }
+                    }
+
+                    if ui.button("Import from Storage").clicked() {
+                        if let Some(storage) = storage.as_deref_mut() {
+                            properties.import_blocklist_from_storage(storage);
+                        }
+                    }
+                });
+
+                ui.add_space(4.0);
+                ui.add(
+                    TextEdit::singleline(&mut properties.blocklist_file_path)
+                        .hint_text("blocklist.json"),
+                );
+
+                ui.horizontal(|ui| {
+                    if ui.button("Export to File").clicked() {
+                        let _ = properties.export_blocklist_to_file();
+                    }
+
+                    if ui.button("Import from File").clicked() {
+                        let _ = properties.import_blocklist_from_file();
+                    }
+                });
+            });
+
             ui.with_layout(egui::Layout::bottom_up(egui::Align::Min), |ui| {
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::BOTTOM), |ui| {
-                    match *current_proxy_state {
+                    match &current_proxy_state {
                         ProxyEvent::Running => {
                             let stop_button = egui::Button::new("Stop Proxy").min_size(Vec2 {
                                 x: ui.available_width() / 2.,
@@ -97,12 +379,12 @@ pub fn main_body(
                                 ui.add_enabled(properties.start_server_capable, stop_button);
 
                             if stop_button_response.clicked() {
-                                proxy_event_sender.send(ProxyEvent::Terminating).unwrap();
+                                properties.proxy.stop();
                             }
                         }
                         _ => {
-                            let start_button = egui::Button::new(match *current_proxy_state {
-                                ProxyEvent::Error => "Retry Proxy",
+                            let start_button = egui::Button::new(match &current_proxy_state {
+                                ProxyEvent::Error(_) => "Retry Proxy",
                                 _ => "Start Proxy",
                             })
                             .min_size(Vec2 {
@@ -113,20 +395,60 @@ pub fn main_body(
                                 ui.add_enabled(properties.start_server_capable, start_button);
 
                             if start_button_response.clicked() {
-                                let port_copy =
-                                    properties.port.trim().parse::<u16>().unwrap().clone();
-                                let proxy_status = properties.proxy_status.clone();
-
-                                // Create a thread and assign the server to it
-                                // This stops the UI from freezing
-                                thread::spawn(move || {
-                                    proxy_service(
-                                        SocketAddr::from(([127, 0, 0, 1], port_copy)),
-                                        proxy_event_sender,
-                                        proxy_status,
-                                        // request_event_sender,
-                                    )
+                                // The enabled state already tracks both fields' validity, but
+                                // re-check here too rather than risk a panic on stale text.
+                                let Ok(port_copy) = properties.port.trim().parse::<u16>() else {
+                                    properties.port_error = "Port contains invalid characters.".to_string();
+                                    properties.set_port_validity(false);
+                                    return;
+                                };
+                                let bind_ip = parse_bind_address(&properties.bind_address)
+                                    .map(|(address, _)| address)
+                                    .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+
+                                let upstream = (!properties.upstream_address.is_empty()).then(|| {
+                                    UpstreamProxy {
+                                        scheme: properties.upstream_scheme,
+                                        address: properties.upstream_address.clone(),
+                                        username: (!properties.upstream_username.is_empty())
+                                            .then(|| properties.upstream_username.clone()),
+                                        password: (!properties.upstream_password.is_empty())
+                                            .then(|| properties.upstream_password.clone()),
+                                    }
                                 });
+
+                                let backends: Vec<String> = properties
+                                    .backend_pool_input
+                                    .split(',')
+                                    .map(|backend| backend.trim().to_string())
+                                    .filter(|backend| !backend.is_empty())
+                                    .collect();
+
+                                let backend_pool = (!backends.is_empty())
+                                    .then(|| std::sync::Arc::new(BackendPool::new(backends)));
+
+                                let filtering_enabled =
+                                    properties.proxy.get_traffic_filter().get_enabled();
+                                if !properties.blocked_hosts.is_empty() && !filtering_enabled {
+                                    properties.proxy.toggle_traffic_filtering();
+                                } else if properties.blocked_hosts.is_empty() && filtering_enabled {
+                                    properties.proxy.toggle_traffic_filtering();
+                                }
+                                properties.proxy.set_exclusion_list(properties.blocked_hosts.clone());
+
+                                properties.proxy.port = port_copy.to_string();
+                                properties.proxy.bind_address = bind_ip.to_string();
+                                properties.proxy.upstream = upstream;
+                                properties.proxy.backend_pool = backend_pool;
+                                properties.proxy.max_connections =
+                                    properties.max_connections_input.trim().parse().ok();
+                                properties.proxy.max_connection_rate =
+                                    properties.max_connection_rate_input.trim().parse().ok();
+                                properties.proxy.request_log_path =
+                                    (!properties.request_log_path_input.is_empty())
+                                        .then(|| properties.request_log_path_input.clone());
+
+                                properties.proxy.run();
                             }
                         }
                     }
@@ -135,14 +457,16 @@ pub fn main_body(
                         x: ui.available_width(),
                         y: 18.,
                     });
-                    ui.add_enabled(false, logs_button);
+                    if ui.add_enabled(true, logs_button).clicked() {
+                        properties.show_logs = !properties.show_logs;
+                    }
                 });
 
                 ui.with_layout(egui::Layout::left_to_right(egui::Align::BOTTOM), |ui| {
                     ui.add(egui::Label::new("Process is currently:"));
                     ui.add(egui::Label::new(
-                        RichText::new(format!("{:?}", current_proxy_state)).color(
-                            match *current_proxy_state {
+                        RichText::new(current_proxy_state.to_string()).color(
+                            match &current_proxy_state {
                                 ProxyEvent::Running => Color32::LIGHT_GREEN,
                                 _ => Color32::LIGHT_RED,
                             },
@@ -156,3 +480,19 @@ pub fn main_body(
             });
         });
 }
+
+/// Parses a bind-address field that's either a bare IP ("0.0.0.0") or a combined
+/// "host:port" address ("0.0.0.0:8000"), returning the IP and, if one was given
+/// alongside it, the port.
+///
+/// # Arguments
+/// * `input` - The raw bind-address field text.
+fn parse_bind_address(input: &str) -> Result<(IpAddr, Option<u16>), ()> {
+    let trimmed = input.trim();
+
+    if let Ok(socket_addr) = trimmed.parse::<SocketAddr>() {
+        return Ok((socket_addr.ip(), Some(socket_addr.port())));
+    }
+
+    trimmed.parse::<IpAddr>().map(|address| (address, None)).map_err(|_| ())
+}
@@ -1,18 +1,134 @@
+//! The proxy engine: accepts connections, applies the traffic filter, and
+//! relays bytes (plain HTTP, CONNECT tunnels, and MITM-decrypted HTTPS).
+//! This module is the only proxy implementation that ships; an earlier
+//! raw-TCP `proxy_handler` was folded in here and removed.
+
 use super::traffic_filter::TrafficFilter;
 use crate::utils::logger::{LogLevel, Logger};
+use base64::Engine;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{
-    body::Bytes, http, server::conn::http1, service::service_fn, upgrade::Upgraded, Method,
-    Request, Response, Uri,
+    body::Bytes,
+    header::{HeaderName, HeaderValue, CONNECTION},
+    http,
+    server::conn::http1,
+    service::service_fn,
+    upgrade::Upgraded,
+    HeaderMap, Method, Request, Response, Uri,
 };
 use hyper_util::rt::TokioIo;
+use rcgen::{CertificateParams, CertifiedKey, DnType, Issuer, KeyPair};
 use std::{
-    net::SocketAddr,
-    sync::{Arc, Mutex},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
     thread,
     time::Duration,
 };
-use tokio::net::{TcpListener, TcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector};
+
+/// The default number of requests kept in memory for the logs view.
+const DEFAULT_REQUEST_LOG_CAPACITY: usize = 500;
+
+/// A pool of interchangeable backend addresses that allowed plain web requests are
+/// distributed across in round-robin order, skipping a backend on connect failure.
+/// CONNECT and MITM tunnels are not routed through the pool.
+#[derive(Debug)]
+pub struct BackendPool {
+    backends: Vec<String>,
+    cursor: AtomicUsize,
+    live_connections: Vec<AtomicU64>,
+}
+
+impl BackendPool {
+    /// Creates a new pool from a list of "host:port" backend addresses.
+    pub fn new(backends: Vec<String>) -> Self {
+        let live_connections = backends.iter().map(|_| AtomicU64::new(0)).collect();
+
+        Self {
+            backends,
+            cursor: AtomicUsize::new(0),
+            live_connections,
+        }
+    }
+
+    /// Returns each backend paired with its current live-connection count, for display.
+    pub fn live_connections(&self) -> Vec<(String, u64)> {
+        self.backends
+            .iter()
+            .cloned()
+            .zip(self.live_connections.iter().map(|count| count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Dials the next backend in round-robin order, skipping over any that refuse
+    /// the connection, until one succeeds or the whole pool has been tried.
+    async fn connect(&self) -> Option<(usize, TcpStream)> {
+        for _ in 0..self.backends.len() {
+            let index = self.cursor.fetch_add(1, Ordering::Relaxed) % self.backends.len();
+
+            if let Ok(stream) = TcpStream::connect(&self.backends[index]).await {
+                return Some((index, stream));
+            }
+        }
+
+        None
+    }
+
+    fn mark_connected(&self, index: usize) {
+        self.live_connections[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn mark_disconnected(&self, index: usize) {
+        self.live_connections[index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A single fault deliberately injected into a proxied connection, for testing how
+/// a client behaves under degraded network conditions.
+#[derive(Debug, Clone)]
+pub enum Toxic {
+    Latency { ms: u64, jitter: u64 },
+    Bandwidth { rate_kbps: u64 },
+    SlowClose { ms: u64 },
+    Timeout { ms: u64 },
+}
+
+/// Which leg of a tunneled connection a Toxic applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ToxicDirection {
+    #[default]
+    Upstream,
+    Downstream,
+}
+
+/// Which protocol a parent proxy speaks.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum UpstreamScheme {
+    Http,
+    Socks5,
+}
+
+/// A parent proxy that outbound connections are chained through instead of being
+/// dialed directly, e.g. a corporate HTTP proxy or a SOCKS5 endpoint.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct UpstreamProxy {
+    pub scheme: UpstreamScheme,
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
 
 /// The enum that controls the current Proxy status, e.g. Running, Stopped.
 #[derive(Debug, PartialEq, Clone, Default)]
@@ -75,6 +191,14 @@ pub struct ProxyRequestLog {
     pub method: String,
     pub request: String,
     pub blocked: bool,
+    /// Milliseconds since the Unix epoch, so exported lines are replayable and sortable.
+    pub timestamp: u64,
+    /// The socket address of the client that made the request.
+    pub client_addr: String,
+    /// The request body's size in bytes, from its Content-Length header (0 if absent).
+    /// Entries are logged at admission time, before the response is streamed back, so
+    /// this reflects only the request side of the exchange and not the full transfer.
+    pub bytes_transferred: u64,
 }
 
 impl ProxyRequestLog {
@@ -115,6 +239,16 @@ pub struct Proxy {
     pub port_error: String,
     pub start_enabled: bool,
 
+    // The address the listening socket is bound to; defaults to loopback-only
+    pub bind_address: String,
+    pub bind_address_error: String,
+
+    // An optional pool of backends that allowed plain web requests are round-robin'd
+    // across, taking priority over `upstream` when both are configured; CONNECT and
+    // MITM tunnels are not routed through it
+    #[serde(skip)]
+    pub backend_pool: Option<Arc<BackendPool>>,
+
     // Which view is currently showing, one of ProxyView
     pub view: ProxyView,
 
@@ -129,13 +263,54 @@ pub struct Proxy {
     #[serde(skip)]
     pub event: Arc<Mutex<Option<std::sync::mpsc::Sender<ProxyEvent>>>>,
 
-    // The list of requests to show in the logs
+    // A bounded ring buffer of the most recent requests, shown in the logs view
     #[serde(skip)]
-    pub requests: Arc<Mutex<Vec<ProxyRequestLog>>>,
+    pub requests: Arc<Mutex<VecDeque<ProxyRequestLog>>>,
+
+    // The maximum number of requests kept in `requests`; older entries are dropped first
+    pub request_log_capacity: usize,
+
+    // An optional NDJSON file that every request is additionally appended to, so a
+    // long-running session's full history isn't bounded by `request_log_capacity`.
+    pub request_log_path: Option<String>,
 
     // Traffic Filters
     pub traffic_filter: Arc<Mutex<TrafficFilter>>,
 
+    // Whether CONNECT tunnels are filtered by the TLS ClientHello's SNI hostname
+    // rather than only the CONNECT authority.
+    pub sni_filtering_enabled: bool,
+
+    // Network faults deliberately injected into proxied connections, per direction
+    #[serde(skip)]
+    pub toxics: Arc<Mutex<Vec<(ToxicDirection, Toxic)>>>,
+
+    // An optional parent proxy that outbound connections are chained through
+    pub upstream: Option<UpstreamProxy>,
+
+    // Whether CONNECT tunnels are intercepted (terminated locally with a generated
+    // leaf certificate) so the decrypted requests can be filtered and logged.
+    pub mitm_enabled: bool,
+
+    // PEM paths for the local CA used to sign per-host leaf certificates when MITM is enabled.
+    pub ca_cert_path: String,
+    pub ca_key_path: String,
+
+    // Leaf certificates generated for MITM, cached per SNI host so repeat connections
+    // to the same host reuse the same identity.
+    #[serde(skip)]
+    pub leaf_cert_cache: Arc<Mutex<HashMap<String, Arc<CertifiedKey>>>>,
+
+    // The maximum number of connections the accept loop will service at once
+    pub max_connections: Option<usize>,
+
+    // The maximum number of new connections accepted per second
+    pub max_connection_rate: Option<u32>,
+
+    // The current number of live connections being served
+    #[serde(skip)]
+    pub live_connections: Arc<AtomicUsize>,
+
     // Different value selectors for exclusion management
     pub selected_value: String,
     pub selected_exclusion_row: ProxyExclusionRow,
@@ -150,7 +325,7 @@ impl Default for Proxy {
     fn default() -> Self {
         let logger = Logger::default();
         let status = Arc::new(Mutex::new(ProxyEvent::default()));
-        let requests = Arc::new(Mutex::new(Vec::<ProxyRequestLog>::new()));
+        let requests = Arc::new(Mutex::new(VecDeque::<ProxyRequestLog>::new()));
         let traffic_filter = Arc::new(Mutex::new(TrafficFilter::default()));
         let run_time = Arc::new(Mutex::new(None));
 
@@ -158,6 +333,9 @@ impl Default for Proxy {
             port: String::default(),
             port_error: String::default(),
             start_enabled: true,
+            bind_address: String::from("127.0.0.1"),
+            bind_address_error: String::default(),
+            backend_pool: None,
             event: Arc::new(Mutex::new(None)),
             selected_value: String::default(),
             selected_exclusion_row: ProxyExclusionRow::default(),
@@ -165,7 +343,19 @@ impl Default for Proxy {
             view: ProxyView::default(),
             logger,
             requests,
+            request_log_capacity: DEFAULT_REQUEST_LOG_CAPACITY,
+            request_log_path: None,
             traffic_filter,
+            sni_filtering_enabled: false,
+            toxics: Arc::new(Mutex::new(Vec::new())),
+            upstream: None,
+            mitm_enabled: false,
+            ca_cert_path: String::default(),
+            ca_key_path: String::default(),
+            leaf_cert_cache: Arc::new(Mutex::new(HashMap::new())),
+            max_connections: None,
+            max_connection_rate: None,
+            live_connections: Arc::new(AtomicUsize::new(0)),
             run_time,
         }
     }
@@ -189,7 +379,7 @@ impl Proxy {
         logger.set_level(log_level);
 
         let status = Arc::new(Mutex::new(ProxyEvent::default()));
-        let requests = Arc::new(Mutex::new(Vec::<ProxyRequestLog>::new()));
+        let requests = Arc::new(Mutex::new(VecDeque::<ProxyRequestLog>::new()));
         let traffic_filter = Arc::new(Mutex::new(traffic_filter));
         let run_time = Arc::new(Mutex::new(None));
 
@@ -197,6 +387,9 @@ impl Proxy {
             port,
             port_error: String::default(),
             start_enabled: true,
+            bind_address: String::from("127.0.0.1"),
+            bind_address_error: String::default(),
+            backend_pool: None,
             event: Arc::new(Mutex::new(None)),
             selected_value: String::default(),
             selected_exclusion_row: ProxyExclusionRow::default(),
@@ -204,7 +397,19 @@ impl Proxy {
             view,
             logger,
             requests,
+            request_log_capacity: DEFAULT_REQUEST_LOG_CAPACITY,
+            request_log_path: None,
             traffic_filter,
+            sni_filtering_enabled: false,
+            toxics: Arc::new(Mutex::new(Vec::new())),
+            upstream: None,
+            mitm_enabled: false,
+            ca_cert_path: String::default(),
+            ca_key_path: String::default(),
+            leaf_cert_cache: Arc::new(Mutex::new(HashMap::new())),
+            max_connections: None,
+            max_connection_rate: None,
+            live_connections: Arc::new(AtomicUsize::new(0)),
             run_time,
         }
     }
@@ -237,6 +442,8 @@ impl Proxy {
         let run_time = self.run_time.clone();
         let status = self.status.clone();
         let requests = self.requests.clone();
+        let request_log_capacity = self.request_log_capacity;
+        let request_log_path = self.request_log_path.clone();
         let event_clone = self.event.clone();
         let logger = self.logger.clone();
 
@@ -287,7 +494,20 @@ impl Proxy {
                             //     }
                             // );
 
-                            requests.lock().unwrap().push(request_log.clone());
+                            let mut requests = requests.lock().unwrap();
+                            if requests.len() >= request_log_capacity {
+                                requests.pop_front();
+                            }
+                            requests.push_back(request_log.clone());
+                            drop(requests);
+
+                            if let Some(path) = &request_log_path {
+                                if let Err(message) = append_request_log(path, &request_log) {
+                                    logger.warning(&format!(
+                                        "Could not append to the request log export: {message}"
+                                    ));
+                                }
+                            }
                         }
                         _ => {
                             *status.lock().unwrap() = event;
@@ -305,8 +525,20 @@ impl Proxy {
     fn handle_server(&self) {
         let event = self.event.lock().unwrap().clone();
         let port = self.port.clone();
+        let bind_address = self.bind_address.clone();
         let status = Arc::clone(&self.status);
         let traffic_filter = Arc::clone(&self.traffic_filter);
+        let sni_filtering_enabled = self.sni_filtering_enabled;
+        let toxics = Arc::clone(&self.toxics);
+        let upstream = self.upstream.clone();
+        let backend_pool = self.backend_pool.clone();
+        let mitm_enabled = self.mitm_enabled;
+        let ca_cert_path = self.ca_cert_path.clone();
+        let ca_key_path = self.ca_key_path.clone();
+        let leaf_cert_cache = Arc::clone(&self.leaf_cert_cache);
+        let max_connections = self.max_connections;
+        let max_connection_rate = self.max_connection_rate;
+        let live_connections = Arc::clone(&self.live_connections);
         let logger = self.logger.clone();
 
         thread::spawn(move || {
@@ -318,22 +550,42 @@ impl Proxy {
                     // Termination Signal
                     let mut signal = std::pin::pin!(handle_termination(event.clone(), status));
 
-                    // Bind to address with supplied port
-                    let address =
-                        SocketAddr::from(([127, 0, 0, 1], port.trim().parse::<u16>().unwrap()));
+                    // Bind to the configured address with the supplied port, falling back to
+                    // loopback-only if the bind address can't be parsed.
+                    let bind_ip = bind_address
+                        .trim()
+                        .parse::<IpAddr>()
+                        .unwrap_or(IpAddr::from([127, 0, 0, 1]));
+                    let address = SocketAddr::from((bind_ip, port.trim().parse::<u16>().unwrap()));
                     let listener = TcpListener::bind(address).await;
 
-                    // Create a request service
                     let proxy_service_event = event.clone();
                     let request_logger = logger.clone();
-                    let proxy_service = service_fn(move |request| {
-                        handle_request(
-                            request,
-                            proxy_service_event.clone(),
-                            traffic_filter.lock().unwrap().clone(),
-                            request_logger.clone(),
-                        )
-                    });
+
+                    // Only load the CA when MITM is opted into; a missing/invalid CA
+                    // falls back to the existing blind tunnel rather than failing startup.
+                    let ca = if mitm_enabled {
+                        match CertificateAuthority::load(&ca_cert_path, &ca_key_path) {
+                            Ok(ca) => Some(Arc::new(ca)),
+                            Err(message) => {
+                                logger.warning(&format!(
+                                    "Could not load the MITM CA, falling back to blind tunneling: {message}"
+                                ));
+                                None
+                            }
+                        }
+                    } else {
+                        None
+                    };
+
+                    // Limits the number of connections being served at once; acquiring a
+                    // permit before accept() provides backpressure via the listen backlog.
+                    let connection_semaphore =
+                        max_connections.map(|max| Arc::new(Semaphore::new(max)));
+
+                    // Token bucket refilled once per second, capping new connections/sec.
+                    let mut rate_tokens = max_connection_rate.unwrap_or(0);
+                    let mut rate_refilled_at = tokio::time::Instant::now();
 
                     // Handle service listener events
                     match listener {
@@ -345,17 +597,74 @@ impl Proxy {
                             logger.global("Service is now running...");
 
                             loop {
+                                let permit = match &connection_semaphore {
+                                    Some(semaphore) => {
+                                        tokio::select! {
+                                            permit = Arc::clone(semaphore).acquire_owned() => Some(permit.unwrap()),
+                                            _ = &mut signal => break,
+                                        }
+                                    }
+                                    None => None,
+                                };
+
+                                if let Some(rate) = max_connection_rate {
+                                    if rate_refilled_at.elapsed() >= Duration::from_secs(1) {
+                                        rate_tokens = rate;
+                                        rate_refilled_at = tokio::time::Instant::now();
+                                    }
+
+                                    if rate_tokens == 0 {
+                                        tokio::select! {
+                                            _ = tokio::time::sleep(Duration::from_millis(50)) => continue,
+                                            _ = &mut signal => break,
+                                        }
+                                    }
+
+                                    rate_tokens -= 1;
+                                }
+
                                 tokio::select! {
-                                    Ok((stream, _addr)) = listener.accept() => {
+                                    Ok((stream, addr)) = listener.accept() => {
                                         let io = TokioIo::new(stream);
+
+                                        let proxy_service_event = proxy_service_event.clone();
+                                        let request_logger = request_logger.clone();
+                                        let traffic_filter = traffic_filter.lock().unwrap().clone();
+                                        let toxics = toxics.lock().unwrap().clone();
+                                        let upstream = upstream.clone();
+                                        let backend_pool = backend_pool.clone();
+                                        let ca = ca.clone();
+                                        let leaf_cert_cache = Arc::clone(&leaf_cert_cache);
+                                        let live_connections = Arc::clone(&live_connections);
+
+                                        live_connections.fetch_add(1, Ordering::Relaxed);
+
+                                        let proxy_service = service_fn(move |request| {
+                                            handle_request(
+                                                request,
+                                                addr,
+                                                proxy_service_event.clone(),
+                                                traffic_filter.clone(),
+                                                sni_filtering_enabled,
+                                                toxics.clone(),
+                                                upstream.clone(),
+                                                backend_pool.clone(),
+                                                ca.clone(),
+                                                leaf_cert_cache.clone(),
+                                                request_logger.clone(),
+                                            )
+                                        });
+
                                         let connection = http1::Builder::new()
                                             .preserve_header_case(true)
                                             .title_case_headers(true)
-                                            .serve_connection(io, proxy_service.clone())
+                                            .serve_connection(io, proxy_service)
                                             .with_upgrades();
 
                                         tokio::task::spawn(async move {
                                             let _ = connection.await;
+                                            live_connections.fetch_sub(1, Ordering::Relaxed);
+                                            drop(permit);
                                         });
                                     },
 
@@ -390,7 +699,7 @@ impl Proxy {
 
     /// Returns the Proxy's recent requests.
     pub fn get_requests(&self) -> Vec<ProxyRequestLog> {
-        self.requests.lock().unwrap().to_vec()
+        self.requests.lock().unwrap().iter().cloned().collect()
     }
 
     /// Returns the Proxy's current running time.
@@ -402,6 +711,11 @@ impl Proxy {
         }
     }
 
+    /// Returns the number of connections currently being served.
+    pub fn get_live_connections(&self) -> usize {
+        self.live_connections.load(Ordering::Relaxed)
+    }
+
     /// Send a ProxyEvent.
     ///
     /// # Arguments:
@@ -420,6 +734,39 @@ impl Proxy {
         self.logger.debug("Traffic filtering has been toggled.");
     }
 
+    /// Toggle SNI-based filtering of CONNECT tunnels on/off.
+    pub fn toggle_sni_filtering(&mut self) {
+        self.sni_filtering_enabled = !self.sni_filtering_enabled;
+        self.logger.debug("SNI filtering has been toggled.");
+    }
+
+    /// Toggle HTTPS interception (MITM) of CONNECT tunnels on/off.
+    pub fn toggle_mitm(&mut self) {
+        self.mitm_enabled = !self.mitm_enabled;
+        self.logger.debug("HTTPS interception has been toggled.");
+    }
+
+    /// Add a Toxic to the given direction of proxied connections.
+    ///
+    /// # Arguments:
+    /// * `direction` - Which leg of the connection the Toxic applies to.
+    /// * `toxic` - The fault to inject.
+    pub fn add_toxic(&self, direction: ToxicDirection, toxic: Toxic) {
+        self.toxics.lock().unwrap().push((direction, toxic));
+        self.logger.debug("A toxic has been added.");
+    }
+
+    /// Removes every configured Toxic.
+    pub fn clear_toxics(&self) {
+        self.toxics.lock().unwrap().clear();
+        self.logger.debug("Toxics have been cleared.");
+    }
+
+    /// Returns the currently configured Toxics.
+    pub fn get_toxics(&self) -> Vec<(ToxicDirection, Toxic)> {
+        self.toxics.lock().unwrap().clone()
+    }
+
     /// Toggle the traffic filter between: TrafficFilterType::Allow / TrafficFilterType::Deny.
     pub fn switch_exclusion_list(&self) {
         let mut traffic_filter = self.traffic_filter.lock().unwrap();
@@ -499,15 +846,32 @@ async fn handle_termination(
 ///
 /// # Arguments:
 /// * `request` - The request to proxy.
+/// * `client_addr` - The socket address of the client that made the request.
 /// * `event` - An internal event sender, to change the Proxy state.
 /// * `traffic_filter` - The current TrafficFilter configuration.
+/// * `sni_filtering_enabled` - Whether CONNECT tunnels should also be filtered by SNI hostname.
+/// * `toxics` - Network faults to inject into this connection, per direction.
+/// * `upstream` - An optional parent proxy that outbound connections are chained through.
+/// * `backend_pool` - An optional pool of backends that plain web requests round-robin across,
+///   taking priority over `upstream` when both are configured. CONNECT/MITM tunnels are relayed
+///   to their actual client-requested target and never go through the pool.
+/// * `ca` - An optional local CA used to intercept (MITM) CONNECT tunnels; `None` tunnels blindly.
+/// * `leaf_cert_cache` - Per-host leaf certificates generated for MITM, reused across connections.
 /// * `logger` - The current logger to log events to.
 async fn handle_request(
     request: Request<hyper::body::Incoming>,
+    client_addr: SocketAddr,
     event: Option<std::sync::mpsc::Sender<ProxyEvent>>,
     traffic_filter: TrafficFilter,
+    sni_filtering_enabled: bool,
+    toxics: Vec<(ToxicDirection, Toxic)>,
+    upstream: Option<UpstreamProxy>,
+    backend_pool: Option<Arc<BackendPool>>,
+    ca: Option<Arc<CertificateAuthority>>,
+    leaf_cert_cache: Arc<Mutex<HashMap<String, Arc<CertifiedKey>>>>,
     logger: Logger,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let connect_event = event.clone();
     let request_uri = request.uri().to_string();
 
     let is_excluded_address = traffic_filter.in_filter_list(&request_uri);
@@ -523,6 +887,9 @@ async fn handle_request(
             method: request.method().to_string(),
             request: request_uri,
             blocked: blocked,
+            timestamp: now_millis(),
+            client_addr: client_addr.to_string(),
+            bytes_transferred: content_length(request.headers()),
         };
 
         logger.debug(
@@ -556,7 +923,37 @@ async fn handle_request(
             tokio::task::spawn(async move {
                 match hyper::upgrade::on(request).await {
                     Ok(upgraded) => {
-                        if let Err(message) = tunnel(upgraded, addr).await {
+                        let result = match ca {
+                            Some(ca) => {
+                                mitm_tunnel(
+                                    upgraded,
+                                    addr,
+                                    client_addr,
+                                    traffic_filter,
+                                    sni_filtering_enabled,
+                                    toxics,
+                                    upstream,
+                                    ca,
+                                    leaf_cert_cache,
+                                    connect_event,
+                                    logger.clone(),
+                                )
+                                .await
+                            }
+                            None => {
+                                tunnel(
+                                    upgraded,
+                                    addr,
+                                    traffic_filter,
+                                    sni_filtering_enabled,
+                                    toxics,
+                                    upstream,
+                                )
+                                .await
+                            }
+                        };
+
+                        if let Err(message) = result {
                             logger.warning(&message.to_string());
                         };
                     }
@@ -577,9 +974,21 @@ async fn handle_request(
     // Proxy web requests
     if let Some(host) = request.uri().host() {
         let port = request.uri().port_u16().unwrap_or(80);
+        let target = format!("{host}:{port}");
 
-        let stream = TcpStream::connect((host, port)).await.unwrap();
-        let io = TokioIo::new(stream);
+        let (stream, backend_index, leftover) = match connect_to_target(&upstream, &backend_pool, &target).await {
+            Ok(connected) => connected,
+            Err(message) => {
+                logger.warning(&message.to_string());
+                let mut resp = Response::new(full("Could not connect to upstream"));
+                *resp.status_mut() = http::StatusCode::BAD_GATEWAY;
+                return Ok(resp);
+            }
+        };
+        let rate_kbps = bandwidth_toxic_rate(&toxics).unwrap_or(0);
+        let slow_close_delay = slow_close_toxic(&toxics);
+        let stream = ThrottledStream::new(stream, rate_kbps);
+        let io = TokioIo::new(PrefixedStream::new(leftover, stream));
 
         let (mut sender, conn) = hyper::client::conn::http1::Builder::new()
             .preserve_header_case(true)
@@ -591,9 +1000,35 @@ async fn handle_request(
             if let Err(message) = conn.await {
                 logger.warning(&message.to_string());
             };
+
+            if let Some(delay) = slow_close_delay {
+                tokio::time::sleep(delay).await;
+            }
+
+            release_backend(&backend_pool, backend_index);
         });
 
-        let response = sender.send_request(request).await?;
+        let mut request = request;
+        strip_hop_by_hop_headers(request.headers_mut());
+        append_forwarding_headers(request.headers_mut(), client_addr, "http");
+
+        apply_latency_toxics(&toxics).await;
+
+        let send_request = sender.send_request(request);
+        let mut response = match timeout_toxic(&toxics) {
+            Some(timeout) => match tokio::time::timeout(timeout, send_request).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    let mut resp = Response::new(full("Upstream request timed out"));
+                    *resp.status_mut() = http::StatusCode::GATEWAY_TIMEOUT;
+                    return Ok(resp);
+                }
+            },
+            None => send_request.await?,
+        };
+
+        strip_hop_by_hop_headers(response.headers_mut());
+
         return Ok(response.map(|b| b.boxed()));
     } else {
         logger.debug(
@@ -609,16 +1044,1179 @@ async fn handle_request(
     }
 }
 
-/// Tunnel a connection bidirectionally.
+/// Tunnel a connection bidirectionally. When `sni_filtering_enabled`, the client's
+/// ClientHello is peeked first so the filter list can be checked against the SNI
+/// hostname rather than only the CONNECT authority; non-TLS payloads fall back to
+/// a blind tunnel.
+///
+/// A CONNECT tunnel is an opaque byte stream to whatever host:port the client asked
+/// for, so unlike the plain web-request path it never round-robins across a
+/// configured `backend_pool` - doing so would silently reroute, say, a client's
+/// `bank.com:443` tunnel to an unrelated backend.
 ///
 /// # Arguments:
 /// * `upgraded` - The upgraded connection to copy data to/from.
 /// * `address` - The target address to copy data to/from.
-async fn tunnel(upgraded: Upgraded, address: String) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(address).await?;
+/// * `traffic_filter` - The current TrafficFilter configuration.
+/// * `sni_filtering_enabled` - Whether to peek the ClientHello and filter by SNI hostname.
+/// * `toxics` - Network faults to inject into this tunnel, per direction.
+/// * `upstream` - An optional parent proxy that the tunnel's target connection is chained through.
+async fn tunnel(
+    upgraded: Upgraded,
+    address: String,
+    traffic_filter: TrafficFilter,
+    sni_filtering_enabled: bool,
+    toxics: Vec<(ToxicDirection, Toxic)>,
+    upstream: Option<UpstreamProxy>,
+) -> std::io::Result<()> {
+    let (mut server, _, leftover) = connect_to_target(&upstream, &None, &address).await?;
     let mut upgraded_connection = TokioIo::new(upgraded);
 
-    tokio::io::copy_bidirectional(&mut upgraded_connection, &mut server).await?;
+    if sni_filtering_enabled && traffic_filter.get_enabled() {
+        let mut client_hello = Vec::new();
+
+        if peek_client_hello(&mut upgraded_connection, &mut client_hello).await? {
+            if let Some(hostname) = extract_sni_hostname(&client_hello) {
+                let is_excluded = traffic_filter.in_filter_list(&hostname);
+                let blocked = is_excluded != traffic_filter.is_blocking();
+
+                if blocked {
+                    // The SNI hostname resolves to a blocked decision; close rather than tunnel.
+                    return Ok(());
+                }
+            }
+        }
+
+        // Whether or not it parsed as a ClientHello, these are real client bytes that
+        // must still reach the server - a non-TLS payload just falls back to a blind
+        // tunnel instead of an SNI-based decision.
+        server.write_all(&client_hello).await?;
+    }
+
+    // Any bytes the upstream already sent past its own handshake response head are
+    // already part of the tunnel's data flow and must reach the client.
+    if !leftover.is_empty() {
+        upgraded_connection.write_all(&leftover).await?;
+    }
+
+    if toxics.is_empty() {
+        tokio::io::copy_bidirectional(&mut upgraded_connection, &mut server).await?;
+        return Ok(());
+    }
+
+    let upstream_toxics: Vec<Toxic> = toxics
+        .iter()
+        .filter(|(direction, _)| *direction == ToxicDirection::Upstream)
+        .map(|(_, toxic)| toxic.clone())
+        .collect();
+    let downstream_toxics: Vec<Toxic> = toxics
+        .iter()
+        .filter(|(direction, _)| *direction == ToxicDirection::Downstream)
+        .map(|(_, toxic)| toxic.clone())
+        .collect();
+
+    let deadline = timeout_toxic(&toxics).map(|timeout| tokio::time::Instant::now() + timeout);
+
+    let (client_read, client_write) = tokio::io::split(upgraded_connection);
+    let (server_read, server_write) = tokio::io::split(server);
+
+    let _ = tokio::join!(
+        copy_with_toxics(client_read, server_write, &upstream_toxics, deadline),
+        copy_with_toxics(server_read, client_write, &downstream_toxics, deadline),
+    );
+
+    Ok(())
+}
+
+/// Releases a backend pool slot acquired by `connect_to_target`, if the connection
+/// came from a pool rather than `upstream`/a direct dial.
+///
+/// # Arguments
+/// * `backend_pool` - The pool the connection may have been dialed from.
+/// * `backend_index` - The pool index returned alongside the connected stream, if any.
+fn release_backend(backend_pool: &Option<Arc<BackendPool>>, backend_index: Option<usize>) {
+    if let (Some(pool), Some(index)) = (backend_pool, backend_index) {
+        pool.mark_disconnected(index);
+    }
+}
+
+/// A local certificate authority used to sign per-host leaf certificates for MITM
+/// interception. The CA's own certificate and private key are read from PEM files on
+/// disk rather than generated at startup, so the same CA can be installed once in the
+/// client's trust store and reused across restarts.
+struct CertificateAuthority {
+    issuer: Issuer<'static, KeyPair>,
+}
+
+impl CertificateAuthority {
+    /// Loads the CA certificate and private key from the given PEM file paths.
+    ///
+    /// # Arguments
+    /// * `cert_path` - Path to the CA's PEM-encoded certificate.
+    /// * `key_path` - Path to the CA's PEM-encoded private key.
+    fn load(cert_path: &str, key_path: &str) -> Result<Self, String> {
+        let cert_pem = std::fs::read_to_string(cert_path)
+            .map_err(|message| format!("could not read CA certificate: {message}"))?;
+        let key_pem = std::fs::read_to_string(key_path)
+            .map_err(|message| format!("could not read CA private key: {message}"))?;
+
+        let key_pair = KeyPair::from_pem(&key_pem)
+            .map_err(|message| format!("could not parse CA private key: {message}"))?;
+        let params = CertificateParams::from_ca_cert_pem(&cert_pem)
+            .map_err(|message| format!("could not parse CA certificate: {message}"))?;
+
+        Ok(Self {
+            issuer: Issuer::new(params, key_pair),
+        })
+    }
+
+    /// Returns a rustls `ServerConfig` presenting a leaf certificate for `hostname`,
+    /// generating and caching one signed by this CA if it isn't already cached.
+    ///
+    /// # Arguments
+    /// * `hostname` - The host the leaf certificate should be valid for.
+    /// * `leaf_cert_cache` - Per-host leaf certificates generated for MITM, reused across connections.
+    fn server_config_for(
+        &self,
+        hostname: &str,
+        leaf_cert_cache: &Arc<Mutex<HashMap<String, Arc<CertifiedKey>>>>,
+    ) -> Result<Arc<rustls::ServerConfig>, String> {
+        let certified_key = {
+            let mut cache = leaf_cert_cache.lock().unwrap();
+            match cache.get(hostname) {
+                Some(certified_key) => certified_key.clone(),
+                None => {
+                    let certified_key = Arc::new(self.sign_leaf(hostname)?);
+                    cache.insert(hostname.to_string(), certified_key.clone());
+                    certified_key
+                }
+            }
+        };
+
+        let cert_der = certified_key.cert.der().clone();
+        let key_der = certified_key.key_pair.serialize_der();
+
+        rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert_der],
+                rustls::pki_types::PrivateKeyDer::Pkcs8(key_der.into()),
+            )
+            .map(Arc::new)
+            .map_err(|message| format!("could not build a TLS server config: {message}"))
+    }
+
+    /// Signs a fresh leaf certificate for `hostname`, valid for that single SAN entry.
+    ///
+    /// # Arguments
+    /// * `hostname` - The host the leaf certificate should be valid for.
+    fn sign_leaf(&self, hostname: &str) -> Result<CertifiedKey, String> {
+        let mut params = CertificateParams::new(vec![hostname.to_string()])
+            .map_err(|message| format!("could not build leaf certificate params: {message}"))?;
+        params
+            .distinguished_name
+            .push(DnType::CommonName, hostname);
+
+        let key_pair =
+            KeyPair::generate().map_err(|message| format!("could not generate a leaf key: {message}"))?;
+        let cert = params
+            .signed_by(&key_pair, &self.issuer)
+            .map_err(|message| format!("could not sign the leaf certificate: {message}"))?;
+
+        Ok(CertifiedKey { cert, key_pair })
+    }
+}
+
+/// Builds a rustls `ClientConfig` trusting the OS's native root certificate store, used
+/// to re-establish TLS to the real upstream after a MITM tunnel decrypts the client leg.
+fn native_root_tls_config() -> Arc<rustls::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+
+    if let Ok(native_certs) = rustls_native_certs::load_native_certs() {
+        for cert in native_certs {
+            let _ = roots.add(cert);
+        }
+    }
+
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+/// Terminates a CONNECT tunnel locally using a leaf certificate signed by the configured
+/// CA, then serves the now-decrypted requests so the existing filter/log logic can see
+/// them in plaintext. Falls back to the existing blind `tunnel` when a leaf certificate
+/// can't be generated for the target host; a failed client TLS handshake just closes
+/// the connection, since there's no unread bytes left to replay into a blind tunnel.
+///
+/// # Arguments:
+/// * `upgraded` - The upgraded CONNECT connection to terminate TLS on.
+/// * `address` - The CONNECT target ("host:port"), used both for the leaf certificate and the real upstream.
+/// * `client_addr` - The socket address of the client that made the request.
+/// * `traffic_filter` - The current TrafficFilter configuration.
+/// * `sni_filtering_enabled` - Whether to also filter by SNI hostname when falling back to a blind tunnel.
+/// * `toxics` - Network faults to inject into this connection, per direction.
+/// * `upstream` - An optional parent proxy that the upstream connection is chained through.
+/// * `ca` - The local CA used to sign the client-facing leaf certificate.
+/// * `leaf_cert_cache` - Per-host leaf certificates generated for MITM, reused across connections.
+/// * `event` - An internal event sender, to change the Proxy state.
+/// * `logger` - The current logger to log events to.
+async fn mitm_tunnel(
+    upgraded: Upgraded,
+    address: String,
+    client_addr: SocketAddr,
+    traffic_filter: TrafficFilter,
+    sni_filtering_enabled: bool,
+    toxics: Vec<(ToxicDirection, Toxic)>,
+    upstream: Option<UpstreamProxy>,
+    ca: Arc<CertificateAuthority>,
+    leaf_cert_cache: Arc<Mutex<HashMap<String, Arc<CertifiedKey>>>>,
+    event: Option<std::sync::mpsc::Sender<ProxyEvent>>,
+    logger: Logger,
+) -> std::io::Result<()> {
+    let host = address
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(&address)
+        .to_string();
+
+    let server_config = match ca.server_config_for(&host, &leaf_cert_cache) {
+        Ok(config) => config,
+        Err(message) => {
+            logger.warning(&format!(
+                "Could not generate a leaf certificate for {host}, falling back to blind tunneling: {message}"
+            ));
+            return tunnel(
+                upgraded,
+                address,
+                traffic_filter,
+                sni_filtering_enabled,
+                toxics,
+                upstream,
+            )
+            .await;
+        }
+    };
+
+    let client_tls = match TlsAcceptor::from(server_config)
+        .accept(TokioIo::new(upgraded))
+        .await
+    {
+        Ok(stream) => stream,
+        Err(message) => {
+            logger.warning(&format!("MITM TLS handshake with the client failed: {message}"));
+            return Ok(());
+        }
+    };
+
+    let io = TokioIo::new(client_tls);
+    let client_tls_config = native_root_tls_config();
+
+    let decrypted_service = service_fn(move |request| {
+        handle_decrypted_request(
+            request,
+            client_addr,
+            address.clone(),
+            traffic_filter.clone(),
+            toxics.clone(),
+            upstream.clone(),
+            client_tls_config.clone(),
+            event.clone(),
+            logger.clone(),
+        )
+    });
+
+    http1::Builder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .serve_connection(io, decrypted_service)
+        .await
+        .map_err(|message| std::io::Error::new(std::io::ErrorKind::Other, message.to_string()))
+}
+
+/// Handles a request decrypted from a MITM-terminated TLS tunnel. An origin-form request
+/// inside a terminated CONNECT tunnel carries a path but no absolute URI, so the
+/// destination is the CONNECT target captured when the tunnel was opened rather than
+/// anything re-derived from the request itself.
+///
+/// # Arguments:
+/// * `request` - The decrypted request to proxy.
+/// * `client_addr` - The socket address of the client that made the request.
+/// * `target` - The CONNECT target ("host:port") this tunnel was opened for.
+/// * `traffic_filter` - The current TrafficFilter configuration.
+/// * `toxics` - Network faults to inject into this connection, per direction.
+/// * `upstream` - An optional parent proxy that outbound connections are chained through.
+/// * `client_tls_config` - The rustls ClientConfig used to re-encrypt to the real upstream.
+/// * `event` - An internal event sender, to change the Proxy state.
+/// * `logger` - The current logger to log events to.
+async fn handle_decrypted_request(
+    request: Request<hyper::body::Incoming>,
+    client_addr: SocketAddr,
+    target: String,
+    traffic_filter: TrafficFilter,
+    toxics: Vec<(ToxicDirection, Toxic)>,
+    upstream: Option<UpstreamProxy>,
+    client_tls_config: Arc<rustls::ClientConfig>,
+    event: Option<std::sync::mpsc::Sender<ProxyEvent>>,
+    logger: Logger,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let request_uri = format!("https://{target}{}", request.uri());
+
+    let is_excluded_address = traffic_filter.in_filter_list(&request_uri);
+    let is_traffic_blocking = traffic_filter.is_blocking();
+
+    if traffic_filter.get_enabled() {
+        let is_blocking_but_exluded = !is_excluded_address && is_traffic_blocking;
+        let is_allowing_but_excluded = is_excluded_address && !is_traffic_blocking;
+        let blocked = is_allowing_but_excluded || is_blocking_but_exluded;
+
+        let request_log = ProxyRequestLog {
+            method: request.method().to_string(),
+            request: request_uri,
+            blocked: blocked,
+            timestamp: now_millis(),
+            client_addr: client_addr.to_string(),
+            bytes_transferred: content_length(request.headers()),
+        };
+
+        logger.debug(
+            format!(
+                "{} -> Request to: {} -> {}",
+                request_log.method,
+                request_log.request,
+                request_log.to_blocked_string()
+            )
+            .as_str(),
+        );
+
+        if let Some(event) = event {
+            event
+                .send(ProxyEvent::RequestEvent(request_log.clone()))
+                .unwrap();
+        }
+
+        if blocked {
+            let mut resp = Response::new(full("Oopsie Whoopsie!"));
+            *resp.status_mut() = http::StatusCode::FORBIDDEN;
+            return Ok(resp);
+        }
+    }
+
+    let (stream, _, leftover) = match connect_to_target(&upstream, &None, &target).await {
+        Ok(connected) => connected,
+        Err(message) => {
+            logger.warning(&message.to_string());
+            let mut resp = Response::new(full("Could not connect to upstream"));
+            *resp.status_mut() = http::StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        }
+    };
+
+    let host = target
+        .rsplit_once(':')
+        .map(|(host, _)| host)
+        .unwrap_or(&target)
+        .to_string();
+
+    let server_name = match rustls::pki_types::ServerName::try_from(host) {
+        Ok(server_name) => server_name,
+        Err(message) => {
+            logger.warning(&message.to_string());
+            let mut resp = Response::new(full("Invalid upstream hostname"));
+            *resp.status_mut() = http::StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        }
+    };
+
+    let rate_kbps = bandwidth_toxic_rate(&toxics).unwrap_or(0);
+    let slow_close_delay = slow_close_toxic(&toxics);
+    let stream = ThrottledStream::new(stream, rate_kbps);
+
+    let stream = match TlsConnector::from(client_tls_config)
+        .connect(server_name, PrefixedStream::new(leftover, stream))
+        .await
+    {
+        Ok(stream) => stream,
+        Err(message) => {
+            logger.warning(&message.to_string());
+            let mut resp = Response::new(full("Upstream TLS handshake failed"));
+            *resp.status_mut() = http::StatusCode::BAD_GATEWAY;
+            return Ok(resp);
+        }
+    };
+
+    let io = TokioIo::new(stream);
+
+    let (mut sender, conn) = hyper::client::conn::http1::Builder::new()
+        .preserve_header_case(true)
+        .title_case_headers(true)
+        .handshake(io)
+        .await?;
+
+    tokio::task::spawn(async move {
+        if let Err(message) = conn.await {
+            logger.warning(&message.to_string());
+        };
+
+        if let Some(delay) = slow_close_delay {
+            tokio::time::sleep(delay).await;
+        }
+    });
+
+    let mut request = request;
+    strip_hop_by_hop_headers(request.headers_mut());
+    append_forwarding_headers(request.headers_mut(), client_addr, "https");
+
+    apply_latency_toxics(&toxics).await;
+
+    let send_request = sender.send_request(request);
+    let mut response = match timeout_toxic(&toxics) {
+        Some(timeout) => match tokio::time::timeout(timeout, send_request).await {
+            Ok(result) => result?,
+            Err(_) => {
+                let mut resp = Response::new(full("Upstream request timed out"));
+                *resp.status_mut() = http::StatusCode::GATEWAY_TIMEOUT;
+                return Ok(resp);
+            }
+        },
+        None => send_request.await?,
+    };
+
+    strip_hop_by_hop_headers(response.headers_mut());
+
+    Ok(response.map(|b| b.boxed()))
+}
+
+/// Copies bytes from `reader` to `writer`, applying each configured Toxic (latency,
+/// bandwidth throttling, an overall deadline, then a slow close) before the function
+/// returns. Replaces a plain `copy_bidirectional` leg so fault injection can be
+/// layered in per direction.
+///
+/// # Arguments
+/// * `reader` - The source half of the connection.
+/// * `writer` - The destination half of the connection.
+/// * `toxics` - The faults configured for this direction.
+/// * `deadline` - An optional instant past which the copy stops, from a Timeout toxic.
+async fn copy_with_toxics<R, W>(
+    mut reader: R,
+    mut writer: W,
+    toxics: &[Toxic],
+    deadline: Option<tokio::time::Instant>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let rate_kbps = toxics.iter().find_map(|toxic| match toxic {
+        Toxic::Bandwidth { rate_kbps } => Some(*rate_kbps),
+        _ => None,
+    });
+
+    let mut bucket_tokens = rate_kbps.map(|rate| rate * 1024 / 8).unwrap_or(0);
+    let mut bucket_refilled_at = tokio::time::Instant::now();
+
+    let mut total = 0u64;
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        if let Some(deadline) = deadline {
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+
+        let mut chunk = &buffer[..read];
+
+        for toxic in toxics {
+            match toxic {
+                Toxic::Latency { ms, jitter } => {
+                    tokio::time::sleep(Duration::from_millis(ms + jittered(*jitter))).await;
+                }
+                Toxic::Bandwidth { rate_kbps } if *rate_kbps == 0 => {
+                    // A zero rate has nothing to refill to, so it can't be throttled
+                    // without spinning forever - treat it as unconfigured instead.
+                }
+                Toxic::Bandwidth { rate_kbps } => loop {
+                    if bucket_refilled_at.elapsed() >= Duration::from_secs(1) {
+                        bucket_tokens = rate_kbps * 1024 / 8;
+                        bucket_refilled_at = tokio::time::Instant::now();
+                    }
+
+                    while bucket_tokens == 0 {
+                        tokio::time::sleep(Duration::from_millis(50)).await;
+                        if bucket_refilled_at.elapsed() >= Duration::from_secs(1) {
+                            bucket_tokens = rate_kbps * 1024 / 8;
+                            bucket_refilled_at = tokio::time::Instant::now();
+                        }
+                    }
+
+                    let allowed = (bucket_tokens as usize).min(chunk.len());
+                    bucket_tokens -= allowed as u64;
+                    if allowed == chunk.len() {
+                        break;
+                    }
+
+                    // Write what the bucket allows now and wait for a refill for the rest,
+                    // so throttling delays bytes instead of dropping them.
+                    writer.write_all(&chunk[..allowed]).await?;
+                    total += allowed as u64;
+                    chunk = &chunk[allowed..];
+                }
+                Toxic::SlowClose { .. } | Toxic::Timeout { .. } => {}
+            }
+        }
+
+        writer.write_all(chunk).await?;
+        total += chunk.len() as u64;
+    }
+
+    for toxic in toxics {
+        if let Toxic::SlowClose { ms } = toxic {
+            tokio::time::sleep(Duration::from_millis(*ms)).await;
+        }
+    }
+
+    Ok(total)
+}
+
+/// Sleeps for the latency configured across `toxics`, if any, before the caller proceeds.
+///
+/// # Arguments
+/// * `toxics` - The faults configured for this request.
+async fn apply_latency_toxics(toxics: &[(ToxicDirection, Toxic)]) {
+    for (_, toxic) in toxics {
+        if let Toxic::Latency { ms, jitter } = toxic {
+            tokio::time::sleep(Duration::from_millis(ms + jittered(*jitter))).await;
+        }
+    }
+}
+
+/// Returns the configured Timeout toxic's duration, if one is present.
+///
+/// # Arguments
+/// * `toxics` - The faults configured for this request.
+fn timeout_toxic(toxics: &[(ToxicDirection, Toxic)]) -> Option<Duration> {
+    toxics.iter().find_map(|(_, toxic)| match toxic {
+        Toxic::Timeout { ms } => Some(Duration::from_millis(*ms)),
+        _ => None,
+    })
+}
+
+/// Picks a random jitter amount up to `max_ms`, or `0` when no jitter is configured.
+///
+/// # Arguments
+/// * `max_ms` - The upper bound (inclusive) of the jitter range, in milliseconds.
+fn jittered(max_ms: u64) -> u64 {
+    if max_ms == 0 {
+        return 0;
+    }
+
+    rand::random::<u64>() % (max_ms + 1)
+}
+
+/// Milliseconds since the Unix epoch, for stamping a `ProxyRequestLog`.
+fn now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Appends `request_log` as a single NDJSON line to the file at `path`, creating it
+/// if it doesn't exist yet.
+///
+/// # Arguments
+/// * `path` - The NDJSON file to append to.
+/// * `request_log` - The request to serialize and append.
+fn append_request_log(path: &str, request_log: &ProxyRequestLog) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut line = serde_json::to_string(request_log)
+        .map_err(|message| std::io::Error::new(std::io::ErrorKind::InvalidData, message))?;
+    line.push('\n');
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Reads the client's first TLS record into `buffer`, looping until the full record
+/// length is available or a safety cap is hit. Returns `false` (with whatever was
+/// read left in `buffer` for replay) when the first byte isn't a TLS handshake record
+/// (content type `0x16`), so the caller can fall back to the existing host-based decision.
+///
+/// # Arguments
+/// * `upgraded_connection` - The upgraded client connection to peek the ClientHello from.
+/// * `buffer` - Accumulates the bytes read so they can be replayed to the upstream.
+async fn peek_client_hello<S: AsyncRead + Unpin>(
+    upgraded_connection: &mut S,
+    buffer: &mut Vec<u8>,
+) -> std::io::Result<bool> {
+    const TLS_RECORD_HEADER_LEN: usize = 5;
+    const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let read = upgraded_connection.read(&mut chunk).await?;
+        if read == 0 {
+            return Ok(false);
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if buffer.len() < TLS_RECORD_HEADER_LEN {
+            continue;
+        }
+
+        // TLS handshake record; anything else isn't a ClientHello we can parse.
+        if buffer[0] != 0x16 {
+            return Ok(false);
+        }
+
+        let record_len = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+        if buffer.len() >= TLS_RECORD_HEADER_LEN + record_len {
+            return Ok(true);
+        }
+
+        if buffer.len() >= MAX_CLIENT_HELLO_LEN {
+            return Ok(true);
+        }
+    }
+}
+
+/// Walks a buffered TLS record for a ClientHello's `server_name` extension and
+/// returns the SNI hostname, if present.
+///
+/// # Arguments
+/// * `record` - The raw bytes of the client's first TLS record.
+fn extract_sni_hostname(record: &[u8]) -> Option<String> {
+    use tls_parser::{parse_tls_extensions, parse_tls_plaintext, TlsExtension, TlsMessage, TlsMessageHandshake};
+
+    let (_, plaintext) = parse_tls_plaintext(record).ok()?;
+
+    for message in plaintext.msg {
+        let TlsMessage::Handshake(TlsMessageHandshake::ClientHello(client_hello)) = message else {
+            continue;
+        };
+
+        let raw_extensions = client_hello.ext?;
+        let (_, extensions) = parse_tls_extensions(raw_extensions).ok()?;
+
+        for extension in extensions {
+            if let TlsExtension::SNI(names) = extension {
+                if let Some((_, hostname)) = names.first() {
+                    return std::str::from_utf8(hostname).ok().map(str::to_string);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// RFC 2616 hop-by-hop headers that are meaningful only for a single transport leg and
+/// must not be relayed between the client and the upstream server.
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailers",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Parses a message's Content-Length header, if present and valid; `0` otherwise.
+///
+/// # Arguments
+/// * `headers` - The headers to read Content-Length from.
+fn content_length(headers: &HeaderMap) -> u64 {
+    headers
+        .get(hyper::header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Removes the hop-by-hop headers from `headers`, along with any header named in the
+/// message's own `Connection` header, so connection-scoped state isn't leaked to the
+/// other leg of the proxy.
+///
+/// # Arguments
+/// * `headers` - The headers to strip, in place.
+fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let named_in_connection: Vec<String> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+
+    let all_headers_to_strip = HOP_BY_HOP_HEADERS
+        .iter()
+        .map(|name| name.to_string())
+        .chain(named_in_connection);
+
+    for name in all_headers_to_strip {
+        if let Ok(header_name) = HeaderName::from_bytes(name.as_bytes()) {
+            headers.remove(header_name);
+        }
+    }
+}
+
+/// Appends the client's address to a comma-separated `X-Forwarded-For`, and sets
+/// `X-Forwarded-Proto` and `Forwarded` so the upstream server can see who it's really
+/// talking to, per the conventions of a standards-compliant forwarding proxy.
+///
+/// # Arguments
+/// * `headers` - The outgoing request headers to annotate, in place.
+/// * `client_addr` - The socket address of the client that made the request.
+/// * `scheme` - The scheme the client originally connected with ("http" or "https"),
+///   since a MITM-decrypted request has no absolute URI to read it back from.
+fn append_forwarding_headers(headers: &mut HeaderMap, client_addr: SocketAddr, scheme: &str) {
+    let client_ip = client_addr.ip().to_string();
+
+    let forwarded_for = match headers.get(HeaderName::from_static("x-forwarded-for")) {
+        Some(existing) if existing.to_str().is_ok() => {
+            format!("{}, {}", existing.to_str().unwrap(), client_ip)
+        }
+        _ => client_ip.clone(),
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert(HeaderName::from_static("x-forwarded-for"), value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(scheme) {
+        headers.insert(HeaderName::from_static("x-forwarded-proto"), value);
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&format!("for={}", client_ip)) {
+        headers.insert(HeaderName::from_static("forwarded"), value);
+    }
+}
+
+/// Wraps a stream so that bytes already consumed from it while reading a handshake
+/// response (and that belong to the data that follows) are replayed to readers before
+/// the stream itself is polled again. Used to hand a connection to a parent proxy's
+/// `CONNECT`/SOCKS handshake back to hyper or rustls without losing any bytes the
+/// handshake read past its own response head.
+struct PrefixedStream<S> {
+    prefix: std::io::Cursor<Vec<u8>>,
+    inner: S,
+}
+
+impl<S> PrefixedStream<S> {
+    fn new(prefix: Vec<u8>, inner: S) -> Self {
+        Self { prefix: std::io::Cursor::new(prefix), inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PrefixedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let remaining = &self.prefix.get_ref()[self.prefix.position() as usize..];
+        if !remaining.is_empty() {
+            let read = remaining.len().min(buf.remaining());
+            buf.put_slice(&remaining[..read]);
+            self.prefix.set_position(self.prefix.position() + read as u64);
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PrefixedStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Wraps a stream so reads and writes are throttled to a configured Bandwidth
+/// toxic's rate, refilling a token bucket once a second the same way
+/// `copy_with_toxics` does for tunneled connections. The web-request and
+/// MITM-decrypted paths hand hyper a single full-duplex stream rather than an
+/// explicit copy loop, so without this, a Bandwidth toxic would silently only take
+/// effect on CONNECT tunnels and not on those two paths.
+struct ThrottledStream<S> {
+    inner: S,
+    rate_kbps: u64,
+    tokens: u64,
+    refilled_at: tokio::time::Instant,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    /// `rate_kbps` of `0` disables throttling entirely rather than stalling forever,
+    /// matching `copy_with_toxics`'s treatment of a zero-rate Bandwidth toxic.
+    fn new(inner: S, rate_kbps: u64) -> Self {
+        Self {
+            inner,
+            rate_kbps,
+            tokens: rate_kbps * 1024 / 8,
+            refilled_at: tokio::time::Instant::now(),
+            sleep: None,
+        }
+    }
+
+    /// Waits until at least one token is available, refilling the bucket once a
+    /// second has elapsed since the last refill.
+    fn poll_wait_for_tokens(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(_) => self.sleep = None,
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+
+            if self.refilled_at.elapsed() >= Duration::from_secs(1) {
+                self.tokens = self.rate_kbps * 1024 / 8;
+                self.refilled_at = tokio::time::Instant::now();
+            }
+
+            if self.tokens > 0 {
+                return Poll::Ready(());
+            }
+
+            self.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_millis(50))));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.rate_kbps == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        if this.poll_wait_for_tokens(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let allowed = (this.tokens as usize).min(buf.remaining());
+        let mut limited = buf.take(allowed);
+        let poll = Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let read = limited.filled().len();
+
+        if let Poll::Ready(Ok(())) = &poll {
+            buf.advance(read);
+            this.tokens = this.tokens.saturating_sub(read as u64);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        if this.rate_kbps == 0 {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        if this.poll_wait_for_tokens(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let allowed = (this.tokens as usize).min(buf.len()).max(1);
+        let poll = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+
+        if let Poll::Ready(Ok(written)) = &poll {
+            this.tokens = this.tokens.saturating_sub(*written as u64);
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Returns the configured Bandwidth toxic's rate, if one is present.
+///
+/// # Arguments
+/// * `toxics` - The faults configured for this request.
+fn bandwidth_toxic_rate(toxics: &[(ToxicDirection, Toxic)]) -> Option<u64> {
+    toxics.iter().find_map(|(_, toxic)| match toxic {
+        Toxic::Bandwidth { rate_kbps } => Some(*rate_kbps),
+        _ => None,
+    })
+}
+
+/// Returns the configured SlowClose toxic's delay, if one is present.
+///
+/// # Arguments
+/// * `toxics` - The faults configured for this request.
+fn slow_close_toxic(toxics: &[(ToxicDirection, Toxic)]) -> Option<Duration> {
+    toxics.iter().find_map(|(_, toxic)| match toxic {
+        Toxic::SlowClose { ms } => Some(Duration::from_millis(*ms)),
+        _ => None,
+    })
+}
+
+/// Reads `stream` up to and including the blank line terminating a response head,
+/// into an owned buffer. Unlike wrapping the stream in a throwaway `BufReader`, this
+/// never silently discards bytes the peer already sent past the terminator - they're
+/// returned alongside the head so the caller can still hand them to whoever reads
+/// the stream next.
+///
+/// # Arguments
+/// * `stream` - The connection to read the response head from.
+async fn read_response_head(stream: &mut TcpStream) -> std::io::Result<(String, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 1024];
+
+    loop {
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            break;
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+
+        if let Some(terminator) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            let leftover = buffer.split_off(terminator + 4);
+            return Ok((String::from_utf8_lossy(&buffer).into_owned(), leftover));
+        }
+    }
+
+    Ok((String::from_utf8_lossy(&buffer).into_owned(), Vec::new()))
+}
+
+/// Opens a connection to `target` ("host:port"). A configured `backend_pool` takes
+/// over destination selection entirely, round-robining across it instead of dialing
+/// `target` directly; otherwise the connection is tunneled through `upstream` when
+/// set, or dialed directly. When the returned stream came from the pool, its index
+/// is also returned so the caller can release it (via `BackendPool::mark_disconnected`)
+/// once the connection ends. Any bytes the upstream already sent past its handshake
+/// response head are returned too, so the caller can still deliver them to whoever
+/// reads the connection next.
+///
+/// # Arguments
+/// * `upstream` - An optional parent proxy to chain the connection through.
+/// * `backend_pool` - An optional pool of backends to round-robin the connection across.
+/// * `target` - The destination address, as "host:port".
+async fn connect_to_target(
+    upstream: &Option<UpstreamProxy>,
+    backend_pool: &Option<Arc<BackendPool>>,
+    target: &str,
+) -> std::io::Result<(TcpStream, Option<usize>, Vec<u8>)> {
+    match backend_pool {
+        Some(pool) => match pool.connect().await {
+            Some((index, stream)) => {
+                pool.mark_connected(index);
+                Ok((stream, Some(index), Vec::new()))
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "every backend in the pool refused the connection",
+            )),
+        },
+        None => match upstream {
+            Some(upstream) => connect_via_upstream(upstream, target)
+                .await
+                .map(|(stream, leftover)| (stream, None, leftover)),
+            None => TcpStream::connect(target).await.map(|stream| (stream, None, Vec::new())),
+        },
+    }
+}
+
+/// Dials the parent proxy's address and performs its handshake, returning a stream
+/// that's ready to relay bytes to/from `target`, along with any bytes the upstream
+/// already sent past its handshake response head - these belong to the connection
+/// that follows and must still reach whoever reads from it next.
+///
+/// # Arguments
+/// * `upstream` - The parent proxy to connect through.
+/// * `target` - The destination address, as "host:port".
+async fn connect_via_upstream(
+    upstream: &UpstreamProxy,
+    target: &str,
+) -> std::io::Result<(TcpStream, Vec<u8>)> {
+    let mut stream = TcpStream::connect(&upstream.address).await?;
+
+    let leftover = match upstream.scheme {
+        UpstreamScheme::Http => connect_via_http_connect(&mut stream, upstream, target).await?,
+        UpstreamScheme::Socks5 => {
+            connect_via_socks5(&mut stream, upstream, target).await?;
+            Vec::new()
+        }
+    };
+
+    Ok((stream, leftover))
+}
+
+/// Issues an HTTP `CONNECT` request to an upstream HTTP proxy and waits for its `200`
+/// response, adding a `Proxy-Authorization: Basic` header when credentials are set.
+/// Returns any bytes the proxy already sent past the response head - unlike reading
+/// just the status line through a throwaway `BufReader`, these are never discarded.
+///
+/// # Arguments
+/// * `stream` - The open connection to the upstream proxy.
+/// * `upstream` - The upstream proxy's configuration.
+/// * `target` - The destination address, as "host:port".
+async fn connect_via_http_connect(
+    stream: &mut TcpStream,
+    upstream: &UpstreamProxy,
+    target: &str,
+) -> std::io::Result<Vec<u8>> {
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+
+    if let (Some(username), Some(password)) = (&upstream.username, &upstream.password) {
+        let credentials =
+            base64::engine::general_purpose::STANDARD.encode(format!("{username}:{password}"));
+        request.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+    }
+
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let (response_head, leftover) = read_response_head(stream).await?;
+
+    if response_head.starts_with("HTTP/1.1 200") || response_head.starts_with("HTTP/1.0 200") {
+        Ok(leftover)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!(
+                "upstream HTTP proxy refused CONNECT: {}",
+                response_head.lines().next().unwrap_or_default()
+            ),
+        ))
+    }
+}
+
+/// Performs the SOCKS5 greeting, optional username/password subnegotiation (RFC 1929),
+/// and `CONNECT` exchange against an upstream SOCKS5 proxy.
+///
+/// # Arguments
+/// * `stream` - The open connection to the upstream proxy.
+/// * `upstream` - The upstream proxy's configuration.
+/// * `target` - The destination address, as "host:port".
+async fn connect_via_socks5(
+    stream: &mut TcpStream,
+    upstream: &UpstreamProxy,
+    target: &str,
+) -> std::io::Result<()> {
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| port.parse::<u16>().ok().map(|port| (host, port)))
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid SOCKS5 target"))?;
+
+    let has_credentials = upstream.username.is_some() && upstream.password.is_some();
+    let methods: &[u8] = if has_credentials { &[0x00, 0x02] } else { &[0x00] };
+
+    let mut greeting = vec![0x05, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut selected = [0u8; 2];
+    stream.read_exact(&mut selected).await?;
+
+    match selected[1] {
+        0x00 => {}
+        0x02 => {
+            let username = upstream.username.as_deref().unwrap_or_default();
+            let password = upstream.password.as_deref().unwrap_or_default();
+
+            let mut auth = vec![0x01, username.len() as u8];
+            auth.extend_from_slice(username.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+
+            let mut auth_response = [0u8; 2];
+            stream.read_exact(&mut auth_response).await?;
+            if auth_response[1] != 0x00 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "SOCKS5 authentication was rejected",
+                ));
+            }
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "SOCKS5 proxy did not accept any offered authentication method",
+            ))
+        }
+    }
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply_header = [0u8; 4];
+    stream.read_exact(&mut reply_header).await?;
+
+    if reply_header[1] != 0x00 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 CONNECT failed with reply code {}", reply_header[1]),
+        ));
+    }
+
+    // Consume the bound address carried in the reply so it isn't mistaken for tunnel data.
+    let bound_addr_len = match reply_header[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "SOCKS5 reply used an unknown address type",
+            ))
+        }
+    };
+
+    let mut discard = vec![0u8; bound_addr_len + 2];
+    stream.read_exact(&mut discard).await?;
 
     Ok(())
 }